@@ -0,0 +1,222 @@
+//! The system bus: DRAM plus the paravirtualized virtio-mmio devices, addressed as a single flat
+//! physical address space. Devices are stored behind the `VirtioDevice` trait and routed to by
+//! MMIO address range instead of each device (or its caller) special-casing the others.
+
+use std::any::Any;
+
+use crate::cpu::Cpu;
+use crate::devices::virtio::Virtio;
+use crate::devices::virtio_device::VirtioDevice;
+use crate::devices::virtio_net::{NullNetBackend, VirtioNet};
+use crate::devices::virtio_rng::VirtioRng;
+use crate::exception::Exception;
+
+/// Where the guest's DRAM starts in the physical address space, matching QEMU's `virt` machine.
+pub const DRAM_BASE: u64 = 0x8000_0000;
+
+/// The size of each virtio device's MMIO window, matching QEMU's `virt` machine.
+const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+/// virtio-blk's MMIO window.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+/// virtio-net's MMIO window, immediately after virtio-blk's.
+pub const VIRTIO_NET_BASE: u64 = VIRTIO_BASE + VIRTIO_MMIO_SIZE;
+/// virtio-rng's MMIO window, immediately after virtio-net's.
+pub const VIRTIO_RNG_BASE: u64 = VIRTIO_NET_BASE + VIRTIO_MMIO_SIZE;
+
+/// Offset, relative to a device's base, of the `VIRTIO_QUEUE_NOTIFY`/`VIRTIO_NET_QUEUE_NOTIFY`/
+/// `VIRTIO_RNG_QUEUE_NOTIFY` register shared by every virtio-mmio device. A write here is what
+/// `Bus` turns into a `VirtioDevice::handle_notify` call.
+const QUEUE_NOTIFY_OFFSET: u64 = 0x050;
+/// Offset, relative to a device's base, of the `VIRTIO_STATUS`/`VIRTIO_NET_STATUS`/
+/// `VIRTIO_RNG_STATUS` register shared by every virtio-mmio device. A write that sets
+/// `DRIVER_OK` here is what `Bus` turns into a `VirtioDevice::activate` call.
+const STATUS_OFFSET: u64 = 0x070;
+/// 2.1 Device Status Field: "DRIVER_OK: Indicates that the driver is set up and ready to drive
+/// the device."
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+/// Index into `Bus::devices` for each virtio device, used by the typed accessors below to
+/// downcast back from `dyn VirtioDevice` to the concrete type the rest of the emulator needs
+/// (e.g. to load a disk image or reseed the RNG).
+const VIRTIO_BLK_INDEX: usize = 0;
+const VIRTIO_NET_INDEX: usize = 1;
+const VIRTIO_RNG_INDEX: usize = 2;
+
+pub struct Bus {
+    dram: Vec<u8>,
+    /// Every virtio device, mapped at its own `VIRTIO_MMIO_SIZE`-byte window starting at the
+    /// matching entry in `device_bases`. Wrapped in `Option` so `mmio_write` can `take()` a
+    /// device out of the slot before calling `handle_notify`/`activate` with `cpu`: those need
+    /// DMA access to guest memory through `cpu.bus`, which would otherwise alias the very device
+    /// `self` refers to. The slot is only ever `None` for the duration of that one call.
+    devices: Vec<Option<Box<dyn VirtioDevice>>>,
+    device_bases: Vec<u64>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        let devices: Vec<Option<Box<dyn VirtioDevice>>> = vec![
+            Some(Box::new(Virtio::new())),
+            Some(Box::new(VirtioNet::new(Box::new(NullNetBackend)))),
+            Some(Box::new(VirtioRng::new())),
+        ];
+        Self {
+            dram: Vec::new(),
+            devices,
+            device_bases: vec![VIRTIO_BASE, VIRTIO_NET_BASE, VIRTIO_RNG_BASE],
+        }
+    }
+
+    /// Set binary data to the beginning of the DRAM from the emulator console.
+    pub fn set_dram(&mut self, data: Vec<u8>) {
+        self.dram = data;
+    }
+
+    /// Set binary data to the virtio disk from the emulator console.
+    pub fn set_disk(&mut self, data: Vec<u8>) {
+        self.virtio().set_disk(data);
+    }
+
+    /// Typed access to the virtio-blk device, downcast from the trait object `Bus` actually
+    /// stores it as.
+    pub fn virtio(&mut self) -> &mut Virtio {
+        self.device_mut(VIRTIO_BLK_INDEX)
+    }
+
+    /// Typed access to the virtio-net device, downcast from the trait object `Bus` actually
+    /// stores it as.
+    pub fn virtio_net(&mut self) -> &mut VirtioNet {
+        self.device_mut(VIRTIO_NET_INDEX)
+    }
+
+    /// Typed access to the virtio-rng device, downcast from the trait object `Bus` actually
+    /// stores it as.
+    pub fn virtio_rng(&mut self) -> &mut VirtioRng {
+        self.device_mut(VIRTIO_RNG_INDEX)
+    }
+
+    fn device_mut<T: 'static>(&mut self, index: usize) -> &mut T {
+        self.devices[index]
+            .as_mut()
+            .expect("device taken out of the bus and not yet put back")
+            .as_any_mut()
+            .downcast_mut::<T>()
+            .expect("bus device slot holds the wrong concrete device type")
+    }
+
+    /// The index of the device whose MMIO window contains `addr`, or `None` if it falls outside
+    /// every device's window.
+    fn device_index_for(&self, addr: u64) -> Option<usize> {
+        self.device_bases
+            .iter()
+            .position(|&base| addr >= base && addr < base + VIRTIO_MMIO_SIZE)
+    }
+
+    /// Read a register from whichever device's MMIO window contains `addr`, or 0 if `addr`
+    /// doesn't fall inside any device's window.
+    pub fn mmio_read(&self, addr: u64) -> u32 {
+        match self.device_index_for(addr) {
+            Some(index) => self.devices[index]
+                .as_ref()
+                .expect("device taken out of the bus and not yet put back")
+                .read(addr),
+            None => 0,
+        }
+    }
+
+    /// Write a register in whichever device's MMIO window contains `addr`. Writing the shared
+    /// `QueueNotify` offset additionally dispatches `VirtioDevice::handle_notify` for the queue
+    /// index in `val`; writing the shared `Status` offset with `DRIVER_OK` set additionally
+    /// invokes `VirtioDevice::activate`. Takes `cpu` rather than being a `&mut self` method
+    /// because both of those callbacks need DMA access to guest memory through `cpu.bus`, which
+    /// would otherwise alias the very device `self` refers to; taking the device out of
+    /// `devices` for the duration of the call keeps the two borrows disjoint.
+    pub fn mmio_write(cpu: &mut Cpu, addr: u64, val: u32) -> Result<(), Exception> {
+        let index = match cpu.bus.device_index_for(addr) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let offset = addr - cpu.bus.device_bases[index];
+
+        let mut device = cpu.bus.devices[index]
+            .take()
+            .expect("device taken out of the bus and not yet put back");
+        device.write(addr, val);
+        let result = match offset {
+            QUEUE_NOTIFY_OFFSET => device.handle_notify(val as usize, cpu),
+            STATUS_OFFSET if val & VIRTIO_STATUS_DRIVER_OK != 0 => device.activate(),
+            _ => Ok(()),
+        };
+        cpu.bus.devices[index] = Some(device);
+        result
+    }
+
+    fn dram_byte(&self, addr: u64) -> u8 {
+        if self.dram.is_empty() {
+            return 0;
+        }
+        self.dram[addr.wrapping_sub(DRAM_BASE) as usize % self.dram.len()]
+    }
+
+    fn set_dram_byte(&mut self, addr: u64, val: u8) {
+        if self.dram.is_empty() {
+            return;
+        }
+        let len = self.dram.len();
+        self.dram[addr.wrapping_sub(DRAM_BASE) as usize % len] = val;
+    }
+
+    pub fn read8(&self, addr: u64) -> Result<u64, Exception> {
+        Ok(self.dram_byte(addr) as u64)
+    }
+
+    pub fn read16(&self, addr: u64) -> Result<u64, Exception> {
+        let mut val = 0;
+        for i in 0..2 {
+            val |= (self.dram_byte(addr + i) as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    pub fn read32(&self, addr: u64) -> Result<u64, Exception> {
+        let mut val = 0;
+        for i in 0..4 {
+            val |= (self.dram_byte(addr + i) as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    pub fn read64(&self, addr: u64) -> Result<u64, Exception> {
+        let mut val = 0;
+        for i in 0..8 {
+            val |= (self.dram_byte(addr + i) as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    pub fn write8(&mut self, addr: u64, val: u64) -> Result<(), Exception> {
+        self.set_dram_byte(addr, val as u8);
+        Ok(())
+    }
+
+    pub fn write16(&mut self, addr: u64, val: u64) -> Result<(), Exception> {
+        for i in 0..2 {
+            self.set_dram_byte(addr + i, (val >> (i * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    pub fn write32(&mut self, addr: u64, val: u64) -> Result<(), Exception> {
+        for i in 0..4 {
+            self.set_dram_byte(addr + i, (val >> (i * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    pub fn write64(&mut self, addr: u64, val: u64) -> Result<(), Exception> {
+        for i in 0..8 {
+            self.set_dram_byte(addr + i, (val >> (i * 8)) as u8);
+        }
+        Ok(())
+    }
+}
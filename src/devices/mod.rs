@@ -0,0 +1,8 @@
+//! Paravirtualized MMIO devices. `Bus` (in `crate::bus`) owns one instance of each behind the
+//! `VirtioDevice` trait and routes MMIO reads/writes/notifications to them by address range.
+
+pub mod virtio;
+pub mod virtio_device;
+pub mod virtio_net;
+pub mod virtio_rng;
+pub mod virtqueue;
@@ -1,39 +1,55 @@
 //! The virtio module contains a virtualization standard for a block device.
-//! This is the "legacy" virtio interface.
+//! Both the legacy (version 1) and the version 1.0, a.k.a. "version 2", MMIO transports are
+//! supported; `VIRTIO_VERSION` reports whichever one the device is configured with through
+//! `Virtio::set_version`.
 //!
 //! The virtio spec:
 //! http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1110002
 //! 5.2 Block Device:
 //! http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-2020002
 
+use std::any::Any;
+
 use crate::bus::VIRTIO_BASE;
 use crate::cpu::Cpu;
+use crate::devices::virtio_device::VirtioDevice;
+use crate::devices::virtqueue::{descriptor_chain, push_used, Virtqueue, QUEUE_SIZE, VIRTQ_DESC_F_WRITE};
 use crate::exception::Exception;
 
 /// The interrupt request of virtio.
 pub const VIRTIO_IRQ: u64 = 1;
 
-/// The size of `VRingDesc` struct.
-const VRING_DESC_SIZE: u64 = 16;
-/// The number of virtio descriptors. It must be a power of two.
-const QUEUE_SIZE: u64 = 8;
 /// The size of a sector.
 const SECTOR_SIZE: u64 = 512;
+/// The number of virtqueues a single device may own. A block device only ever uses queue 0; a
+/// network device uses queue 0 for RX and queue 1 for TX.
+const NUM_QUEUES: usize = 2;
 
 // 4.2.2 MMIO Device Register Layout
 // http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1110002
 /// Magic value. Always return 0x74726976 (a Little Endian equivalent of the “virt” string).
 pub const VIRTIO_MAGIC: u64 = VIRTIO_BASE + 0x000;
-/// Device version number. 1 is legacy.
+/// Device version number. 1 is legacy, 2 is the version 1.0 (non-legacy) interface.
 pub const VIRTIO_VERSION: u64 = VIRTIO_BASE + 0x004;
 /// Virtio Subsystem Device ID. 1 is network, 2 is block device.
 pub const VIRTIO_DEVICE_ID: u64 = VIRTIO_BASE + 0x008;
 /// Virtio Subsystem Vendor ID. Always return 0x554d4551
 pub const VIRTIO_VENDOR_ID: u64 = VIRTIO_BASE + 0x00c;
-/// Flags representing features the device supports.
+/// Flags representing features the device supports. Since the feature bits span 64 bits but each
+/// MMIO access is only 32 bits wide, this returns bits `[sel*32 .. sel*32 + 32)` of the device's
+/// 64-bit feature set, where `sel` is the value last written to `VIRTIO_HOST_FEATURES_SEL`.
 pub const VIRTIO_DEVICE_FEATURES: u64 = VIRTIO_BASE + 0x010;
-/// Flags representing device features understood and activated by the driver.
+/// Device (host) features word selection. Writing to this register selects the 32-bit chunk of
+/// `VIRTIO_DEVICE_FEATURES` to expose: 0 selects feature bits 0 to 31, 1 selects feature bits 32
+/// to 63. Write-only.
+pub const VIRTIO_HOST_FEATURES_SEL: u64 = VIRTIO_BASE + 0x014;
+/// Flags representing device features understood and activated by the driver. Writing ORs the
+/// value into bits `[sel*32 .. sel*32 + 32)` of the driver's 64-bit feature set, where `sel` is
+/// the value last written to `VIRTIO_GUEST_FEATURES_SEL`.
 pub const VIRTIO_DRIVER_FEATURES: u64 = VIRTIO_BASE + 0x020;
+/// Activated (guest) features word selection. Writing to this register selects the 32-bit chunk
+/// of the driver feature set that a write to `VIRTIO_DRIVER_FEATURES` applies to. Write-only.
+pub const VIRTIO_GUEST_FEATURES_SEL: u64 = VIRTIO_BASE + 0x024;
 // 4.2.4 Legacy interface
 // http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1210004
 /// Guest page size. The driver writes the guest page size in bytes to the register during
@@ -72,59 +88,50 @@ pub const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
 /// zero (0x0) to this register triggers a device reset.
 pub const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
 
-/// "The descriptor table refers to the buffers the driver is using for the device. addr is a
-/// physical address, and the buffers can be chained via next. Each descriptor describes a buffer
-/// which is read-only for the device (“device-readable”) or write-only for the device
-/// (“device-writable”), but a chain of descriptors can contain both device-readable and
-/// device-writable buffers."
-///
-/// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-300005
-///
-/// ```c
-/// struct virtq_desc {
-///   /* Address (guest-physical). */
-///   le64 addr;
-///   /* Length. */
-///   le32 len;
-///
-///   /* This marks a buffer as continuing via the next field. */
-///   #define VIRTQ_DESC_F_NEXT   1
-///   /* This marks a buffer as device write-only (otherwise device read-only). */
-///   #define VIRTQ_DESC_F_WRITE     2
-///   /* This means the buffer contains a list of buffer descriptors. */
-///   #define VIRTQ_DESC_F_INDIRECT   4
-///   /* The flags as indicated above. */
-///   le16 flags;
-///   /* Next field if flags & NEXT */
-///   le16 next;
-/// };
-/// ```
-struct VirtqDesc {
-    /// 64-bit address.
-    addr: u64,
-    /// 32-bit length.
-    len: u64,
-    /// 16-bit flags.
-    flags: u64,
-    /// 16-bit next.
-    next: u64,
-}
+// 2.1 Device Status Field
+// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-120001
+/// The driver has acknowledged the features it understands, and no subset of them was unknown to
+/// the device. Setting this bit fails activation (`VIRTIO_STATUS_FAILED`) if the driver tried to
+/// ack a feature the device never offered in `VIRTIO_DEVICE_FEATURES`.
+const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+/// Something went wrong, either because the driver gave up or because of the `FEATURES_OK` check
+/// above failing.
+const VIRTIO_STATUS_FAILED: u32 = 128;
 
-impl VirtqDesc {
-    /// Create a new virtqueue descriptor based on the address that stores the content of the descriptor.
-    fn new(cpu: &Cpu, addr: u64) -> Result<Self, Exception> {
-        Ok(Self {
-            addr: cpu.bus.read64(addr)?,
-            len: cpu.bus.read32(addr.wrapping_add(8))?,
-            flags: cpu.bus.read16(addr.wrapping_add(12))?,
-            next: cpu.bus.read16(addr.wrapping_add(14))?,
-        })
-    }
-}
+/// `VIRTIO_F_VERSION_1`. Offered by the device to indicate it supports the version 1.0
+/// (non-legacy) interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// `VIRTIO_BLK_F_RO`. Offered by the device to indicate the disk is read-only.
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// `VIRTIO_BLK_F_BLK_SIZE`. Offered by the device to indicate that `blk_size` is the block size
+/// of the disk and should be used by the driver for optimal alignment.
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+// 4.2.4 Legacy interface / 4.2.2 non-legacy registers
+// http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1090002
+/// Virtual queue ready bit. Writing one (0x1) to this register notifies the device that it can
+/// execute requests from this virtual queue. Reading from this register returns the last value
+/// written to it. Both read and write accesses apply to the queue selected by writing to
+/// QueueSel. Only used in the version 2 (non-legacy) interface.
+pub const VIRTIO_QUEUE_READY: u64 = VIRTIO_BASE + 0x044;
+/// Virtual queue's Descriptor Area 64-bit long physical address, low 32 bits. Write-only.
+pub const VIRTIO_QUEUE_DESC_LOW: u64 = VIRTIO_BASE + 0x080;
+/// Virtual queue's Descriptor Area 64-bit long physical address, high 32 bits. Write-only.
+pub const VIRTIO_QUEUE_DESC_HIGH: u64 = VIRTIO_BASE + 0x084;
+/// Virtual queue's Driver Area (the available ring) 64-bit long physical address, low 32 bits.
+/// Write-only.
+pub const VIRTIO_QUEUE_DRIVER_LOW: u64 = VIRTIO_BASE + 0x090;
+/// Virtual queue's Driver Area (the available ring) 64-bit long physical address, high 32 bits.
+/// Write-only.
+pub const VIRTIO_QUEUE_DRIVER_HIGH: u64 = VIRTIO_BASE + 0x094;
+/// Virtual queue's Device Area (the used ring) 64-bit long physical address, low 32 bits.
+/// Write-only.
+pub const VIRTIO_QUEUE_DEVICE_LOW: u64 = VIRTIO_BASE + 0x0a0;
+/// Virtual queue's Device Area (the used ring) 64-bit long physical address, high 32 bits.
+/// Write-only.
+pub const VIRTIO_QUEUE_DEVICE_HIGH: u64 = VIRTIO_BASE + 0x0a4;
 
 /// Paravirtualized drivers for IO virtualization.
 pub struct Virtio {
-    id: u64,
     /// 2.2 Feature Bits
     /// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-130002
     /// Each virtio device offers all the features it understands.
@@ -132,12 +139,25 @@ pub struct Virtio {
     /// 24 to 40: Feature bits reserved for extensions to the queue and
     ///           feature negotiation mechanisms
     /// 41 to 63: Feature bits reserved for future extensions
-    driver_features: u32,
+    device_features: u64,
+    /// The 32-bit window of `device_features` currently exposed through `VIRTIO_DEVICE_FEATURES`.
+    host_features_sel: u32,
+    /// The features the driver has acknowledged understanding and activated.
+    driver_features: u64,
+    /// The 32-bit window of `driver_features` that a write to `VIRTIO_DRIVER_FEATURES` applies to.
+    guest_features_sel: u32,
     page_size: u32,
+    /// Index of the queue that the QueueNum/QueuePFN/QueueReady/... registers below apply to.
     queue_sel: u32,
-    queue_num: u32,
-    queue_pfn: u32,
-    queue_notify: u32,
+    /// Per-queue register state and ring bookkeeping. The block device only ever uses queue 0.
+    queues: [Virtqueue; NUM_QUEUES],
+    /// The transport version this device reports through `VIRTIO_VERSION`. 1 is the legacy
+    /// interface addressed through `VIRTIO_QUEUE_PFN`; 2 is the version 1.0 (non-legacy)
+    /// interface addressed through the QueueDesc/QueueDriver/QueueDevice register triples. A
+    /// legacy-only driver refuses to bind a device that reports 2, so this is a version the
+    /// device is configured with via `set_version`, not something the driver picks by writing to
+    /// it; it defaults to 1 so an unconfigured device stays compatible with legacy drivers.
+    version: u32,
     /// "The device status field provides a simple low-level indication of the completed steps of
     /// this sequence.
     /// The device MUST initialize device status to 0 upon reset."
@@ -150,25 +170,35 @@ impl Virtio {
     /// Create a new virtio object.
     pub fn new() -> Self {
         Self {
-            id: 0,
+            device_features: VIRTIO_F_VERSION_1,
+            host_features_sel: 0,
             driver_features: 0,
+            guest_features_sel: 0,
             page_size: 0,
             queue_sel: 0,
-            queue_num: 0,
-            queue_pfn: 0,
-            queue_notify: 9999, // TODO: what is the correct initial value?
+            queues: [Virtqueue::new(), Virtqueue::new()],
+            version: 1,
             status: 0,
             disk: Vec::new(),
         }
     }
 
+    /// Configure the transport version this device reports through `VIRTIO_VERSION`: 1 for the
+    /// legacy PFN-addressed queue a legacy-only driver requires, 2 to opt into the version 1.0
+    /// (non-legacy) QueueDesc/QueueDriver/QueueDevice addressing.
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
     /// Return true if an interrupt is pending.
     pub fn is_interrupting(&mut self) -> bool {
-        if self.queue_notify != 9999 {
-            self.queue_notify = 9999;
-            return true;
-        }
-        false
+        self.take_notified_queue().is_some()
+    }
+
+    /// Return the index of a queue that was notified since the last call, clearing its flag, or
+    /// `None` if no queue has a pending notification.
+    pub fn take_notified_queue(&mut self) -> Option<usize> {
+        self.queues.iter_mut().position(|queue| queue.take_notified())
     }
 
     /// Set the binary in the virtio disk.
@@ -176,17 +206,28 @@ impl Virtio {
         self.disk.extend(binary.iter().cloned());
     }
 
+    /// Declare the features this device offers in addition to `VIRTIO_F_VERSION_1`, e.g.
+    /// `VIRTIO_BLK_F_RO` or `VIRTIO_BLK_F_BLK_SIZE`.
+    pub fn set_device_features(&mut self, features: u64) {
+        self.device_features |= features;
+    }
+
     /// Read 4 bytes from virtio only if the addr is valid. Otherwise, return 0.
     pub fn read(&self, addr: u64) -> u32 {
         match addr {
             VIRTIO_MAGIC => 0x74726976,     // read-only
-            VIRTIO_VERSION => 0x1,          // read-only
+            VIRTIO_VERSION => self.version, // read-only
             VIRTIO_DEVICE_ID => 0x2,        // read-only
             VIRTIO_VENDOR_ID => 0x554d4551, // read-only
-            VIRTIO_DEVICE_FEATURES => 0,    // TODO: what should it return?
-            VIRTIO_DRIVER_FEATURES => self.driver_features,
+            VIRTIO_DEVICE_FEATURES => {
+                (self.device_features >> (self.host_features_sel as u64 * 32)) as u32
+            }
+            VIRTIO_DRIVER_FEATURES => {
+                (self.driver_features >> (self.guest_features_sel as u64 * 32)) as u32
+            }
             VIRTIO_QUEUE_NUM_MAX => 8,
-            VIRTIO_QUEUE_PFN => self.queue_pfn,
+            VIRTIO_QUEUE_PFN => self.selected_queue().map_or(0, Virtqueue::pfn),
+            VIRTIO_QUEUE_READY => self.selected_queue().map_or(0, Virtqueue::ready),
             VIRTIO_STATUS => self.status,
             _ => 0,
         }
@@ -195,24 +236,41 @@ impl Virtio {
     /// Write 4 bytes to virtio only if the addr is valid. Otherwise, does nothing.
     pub fn write(&mut self, addr: u64, val: u32) {
         match addr {
-            VIRTIO_DEVICE_FEATURES => self.driver_features = val,
+            VIRTIO_HOST_FEATURES_SEL => self.host_features_sel = val,
+            VIRTIO_DRIVER_FEATURES => {
+                self.driver_features |= (val as u64) << (self.guest_features_sel as u64 * 32)
+            }
+            VIRTIO_GUEST_FEATURES_SEL => self.guest_features_sel = val,
             VIRTIO_GUEST_PAGE_SIZE => self.page_size = val,
             VIRTIO_QUEUE_SEL => self.queue_sel = val,
-            VIRTIO_QUEUE_NUM => self.queue_num = val,
-            VIRTIO_QUEUE_PFN => self.queue_pfn = val,
-            VIRTIO_QUEUE_NOTIFY => self.queue_notify = val,
+            VIRTIO_QUEUE_NUM => self.selected_queue_mut().map_or((), |q| q.set_num(val)),
+            VIRTIO_QUEUE_PFN => self.selected_queue_mut().map_or((), |q| q.set_pfn(val)),
+            VIRTIO_QUEUE_READY => self.selected_queue_mut().map_or((), |q| q.set_ready(val)),
+            VIRTIO_QUEUE_DESC_LOW => self.selected_queue_mut().map_or((), |q| q.set_desc_low(val)),
+            VIRTIO_QUEUE_DESC_HIGH => self.selected_queue_mut().map_or((), |q| q.set_desc_high(val)),
+            VIRTIO_QUEUE_DRIVER_LOW => self.selected_queue_mut().map_or((), |q| q.set_driver_low(val)),
+            VIRTIO_QUEUE_DRIVER_HIGH => self.selected_queue_mut().map_or((), |q| q.set_driver_high(val)),
+            VIRTIO_QUEUE_DEVICE_LOW => self.selected_queue_mut().map_or((), |q| q.set_device_low(val)),
+            VIRTIO_QUEUE_DEVICE_HIGH => self.selected_queue_mut().map_or((), |q| q.set_device_high(val)),
+            // Writing a queue index to this register notifies the device that queue has new
+            // buffers to process; the index is the payload, not the currently-selected queue. A
+            // guest writing an out-of-range index is ignored rather than indexed directly, same
+            // as an out-of-range QueueSel.
+            VIRTIO_QUEUE_NOTIFY => self.queues.get_mut(val as usize).map_or((), Virtqueue::notify),
             VIRTIO_STATUS => self.status = val,
             _ => {}
         }
     }
 
-    fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        self.id
+    /// The queue `VIRTIO_QUEUE_SEL` currently points at, or `None` if the driver selected an
+    /// out-of-range index.
+    fn selected_queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_sel as usize)
     }
 
-    fn desc_addr(&self) -> u64 {
-        self.queue_pfn as u64 * self.page_size as u64
+    /// Mutable counterpart of `selected_queue`.
+    fn selected_queue_mut(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
     }
 
     fn read_disk(&self, addr: u64) -> u64 {
@@ -224,91 +282,131 @@ impl Virtio {
     }
 
     /// Access the disk via virtio. This is an associated function which takes a `cpu` object to
-    /// read and write with a memory directly (DMA).
-    pub fn disk_access(cpu: &mut Cpu) -> Result<(), Exception> {
+    /// read and write with a memory directly (DMA). The block device only ever uses queue 0.
+    ///
+    /// A single notify can follow more than one `avail` ring push if the driver queued several
+    /// requests back to back, so this processes every head between `last_avail_idx` and the
+    /// driver's current `avail.idx` in one batch, the same way xv6's `virtio_disk_intr` drains
+    /// the ring:
+    /// https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c
+    ///
+    /// Takes `cpu` only to reach guest memory for the DMA (`Bus`'s DRAM accessors); device-local
+    /// state (the queues, the disk image) is reached through `self` directly instead of re-
+    /// entering `cpu.bus`. `Bus::mmio_write` calls this with `self` already taken out of its
+    /// device slot for the duration of the call, so re-fetching the device through `cpu.bus`
+    /// would hit an empty slot.
+    pub fn disk_access(&mut self, cpu: &mut Cpu) -> Result<(), Exception> {
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-230005
         // "Each virtqueue can consist of up to 3 parts:
         //     Descriptor Area - used for describing buffers
         //     Driver Area - extra data supplied by driver to the device
         //     Device Area - extra data supplied by device to driver"
-        //
-        // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L101-L103
-        //     desc = pages -- num * VirtqDesc
-        //     avail = pages + 0x40 -- 2 * uint16, then num * uint16
-        //     used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
-        //
-        // The actual descriptors (16 bytes each).
-        let desc_addr = cpu.bus.virtio.desc_addr();
-        // A ring of available descriptor heads with free-running index.
-        let avail_addr = cpu.bus.virtio.desc_addr() + 0x40;
-        // A ring of used descriptor heads with free-running index.
-        let used_addr = cpu.bus.virtio.desc_addr() + 4096;
+        let (desc_addr, avail_addr, used_addr) = self.queues[0].addrs(self.page_size);
 
         // 2.4.6 The Virtqueue Available Ring
         // http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-360006
-        // struct virtq_avail {
-        //   #define VIRTQ_AVAIL_F_NO_INTERRUPT      1
-        //   le16 flags;
-        //   le16 idx;
-        //   le16 ring[ /* Queue Size */ ];
-        //   le16 used_event; /* Only if VIRTIO_F_EVENT_IDX */
-        // };
         //
         // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L230-L234
         // "avail[0] is flags
         //  avail[1] tells the device how far to look in avail[2...].
         //  avail[2...] are desc[] indices the device should process.
         //  we only tell device the first index in our chain of descriptors."
-        let offset = cpu.bus.read16(avail_addr.wrapping_add(1))?;
-        let index = cpu
-            .bus
-            .read16(avail_addr.wrapping_add(offset % QUEUE_SIZE).wrapping_add(2))?;
-
-        // First descriptor.
-        let desc0 = VirtqDesc::new(cpu, desc_addr + VRING_DESC_SIZE * index)?;
-
-        // Second descriptor.
-        let desc1 = VirtqDesc::new(cpu, desc_addr + VRING_DESC_SIZE * desc0.next)?;
-
-        // 5.2.6 Device Operation
-        // http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-2130006
-        // struct virtio_blk_req {
-        //   le32 type;
-        //   le32 reserved;
-        //   le64 sector;
-        //   u8 data[][512];
-        //   u8 status;
-        // };
-        let sector = cpu.bus.read64(desc0.addr.wrapping_add(8))?;
-
-        // Write to a device if the second bit of `flags` is set.
-        match (desc1.flags & 2) == 0 {
-            true => {
-                // Read memory data and write it to a disk directly (DMA).
-                for i in 0..desc1.len {
-                    let data = cpu.bus.read8(desc1.addr + i)?;
-                    cpu.bus.virtio.write_disk(sector * SECTOR_SIZE + i, data);
-                }
-            }
-            false => {
-                // Read disk data and write it to memory directly (DMA).
-                for i in 0..desc1.len {
-                    let data = cpu.bus.virtio.read_disk(sector * SECTOR_SIZE + i);
-                    cpu.bus.write8(desc1.addr + i, data)?;
+        let avail_idx = cpu.bus.read16(avail_addr.wrapping_add(2))? as u16;
+        while self.queues[0].last_avail_idx != avail_idx {
+            let offset = self.queues[0].last_avail_idx;
+            let head = cpu.bus.read16(
+                avail_addr
+                    .wrapping_add(4)
+                    .wrapping_add(offset as u64 % QUEUE_SIZE * 2),
+            )?;
+            self.queues[0].last_avail_idx = offset.wrapping_add(1);
+
+            // 5.2.6 Device Operation
+            // http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-2130006
+            // struct virtio_blk_req {
+            //   le32 type;
+            //   le32 reserved;
+            //   le64 sector;
+            //   u8 data[][512];
+            //   u8 status;
+            // };
+            //
+            // The first descriptor in the chain is the request header (giving us
+            // `sector`); any further device-writable one-byte descriptor is the trailing status
+            // byte; everything else is the data payload.
+            let chain = descriptor_chain(cpu, desc_addr, head)?;
+            let mut sector = 0;
+            let mut header_seen = false;
+            let mut len = 0;
+            for desc in &chain {
+                let writable = desc.flags & VIRTQ_DESC_F_WRITE != 0;
+                if !header_seen {
+                    sector = cpu.bus.read64(desc.addr.wrapping_add(8))?;
+                    header_seen = true;
+                } else if writable && desc.len == 1 {
+                    // VIRTIO_BLK_S_OK
+                    cpu.bus.write8(desc.addr, 0)?;
+                    len += 1;
+                } else if writable {
+                    // Read disk data and write it to memory directly (DMA).
+                    for i in 0..desc.len {
+                        let data = self.read_disk(sector * SECTOR_SIZE + i);
+                        cpu.bus.write8(desc.addr + i, data)?;
+                    }
+                    len += desc.len;
+                } else {
+                    // Read memory data and write it to a disk directly (DMA).
+                    for i in 0..desc.len {
+                        let data = cpu.bus.read8(desc.addr + i)?;
+                        self.write_disk(sector * SECTOR_SIZE + i, data);
+                    }
                 }
             }
-        };
-
-        // http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-400008
-        // struct virtq_used {
-        //   #define VIRTQ_USED_F_NO_NOTIFY  1
-        //   le16 flags;
-        //   le16 idx;
-        //   struct virtq_used_elem ring[ /* Queue Size */];
-        //   le16 avail_event; /* Only if VIRTIO_F_EVENT_IDX */
-        // };
-        let new_id = cpu.bus.virtio.get_new_id();
-        cpu.bus.write16(used_addr.wrapping_add(2), new_id % 8)?;
+
+            push_used(cpu, used_addr, head, len)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Virtio {
+    fn device_id(&self) -> u32 {
+        0x2
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device_features
+    }
+
+    fn activate(&mut self) -> Result<(), Exception> {
+        // 3.1.1 Driver Requirements: reject activation if the driver acknowledged a feature the
+        // device never offered. `write` has already stored the driver's status by the time `Bus`
+        // calls this, so `self.status` reflects whether `FEATURES_OK` was part of that write.
+        if self.status & VIRTIO_STATUS_FEATURES_OK != 0
+            && self.driver_features & !self.device_features != 0
+        {
+            self.status |= VIRTIO_STATUS_FAILED;
+        }
         Ok(())
     }
+
+    fn read(&self, addr: u64) -> u32 {
+        Virtio::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u64, val: u32) {
+        Virtio::write(self, addr, val)
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        Virtio::is_interrupting(self)
+    }
+
+    fn handle_notify(&mut self, _queue_index: usize, cpu: &mut Cpu) -> Result<(), Exception> {
+        self.disk_access(cpu)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
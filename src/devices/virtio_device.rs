@@ -0,0 +1,45 @@
+//! The common interface implemented by every paravirtualized MMIO device (virtio-blk,
+//! virtio-net, virtio-rng, ...), modeled on the device abstraction used by the
+//! rust-vmm/crosvm lineage. The `Bus` holds every device as a `Box<dyn VirtioDevice>`, each mapped at its
+//! own MMIO window, and routes register reads/writes and `QueueNotify` writes to the right
+//! device instead of every device duplicating that dispatch.
+
+use std::any::Any;
+
+use crate::cpu::Cpu;
+use crate::exception::Exception;
+
+/// A paravirtualized device reachable through a virtio-mmio window.
+pub trait VirtioDevice {
+    /// Virtio Subsystem Device ID, e.g. 1 (network), 2 (block), 4 (entropy).
+    fn device_id(&self) -> u32;
+
+    /// The feature bits this device offers, e.g. `VIRTIO_F_VERSION_1` for a device that supports
+    /// the version 1.0 (non-legacy) interface; 0 for a legacy-only device.
+    fn device_features(&self) -> u64;
+
+    /// Called once the driver sets `DRIVER_OK` in the status register. The default does
+    /// nothing; devices that need to validate negotiated feature combinations can override it.
+    fn activate(&mut self) -> Result<(), Exception> {
+        Ok(())
+    }
+
+    /// Read 4 bytes from this device's MMIO window.
+    fn read(&self, addr: u64) -> u32;
+
+    /// Write 4 bytes to this device's MMIO window.
+    fn write(&mut self, addr: u64, val: u32);
+
+    /// Return true if this device has a pending interrupt.
+    fn is_interrupting(&mut self) -> bool;
+
+    /// Process newly available buffers on `queue_index`, invoked when the driver writes that
+    /// index to `QueueNotify`. `cpu` gives access to guest memory for the DMA the device needs
+    /// to perform; device-local state (e.g. a disk image) is reached through `self`.
+    fn handle_notify(&mut self, queue_index: usize, cpu: &mut Cpu) -> Result<(), Exception>;
+
+    /// Downcast back to the concrete device type. `Bus` stores every device behind this trait so
+    /// it can route to them uniformly, but the emulator console still needs typed access to reach
+    /// device-specific setters (e.g. `VirtioRng::set_seed`) that aren't part of this interface.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
@@ -0,0 +1,272 @@
+//! The virtio_net module contains a paravirtualized network device, the second virtio device
+//! type this emulator implements alongside the virtio-blk device in `virtio`.
+//!
+//! The virtio spec:
+//! http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1110002
+//! 5.1 Network Device:
+//! http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1930001
+
+use std::any::Any;
+
+use crate::bus::VIRTIO_NET_BASE;
+use crate::cpu::Cpu;
+use crate::devices::virtio_device::VirtioDevice;
+use crate::devices::virtqueue::{descriptor_chain, push_used, Virtqueue, QUEUE_SIZE, VIRTQ_DESC_F_WRITE};
+use crate::exception::Exception;
+
+/// The interrupt request of virtio-net. This is distinct from the virtio-blk device's
+/// `VIRTIO_IRQ` since each virtio-mmio slot on the PLIC has its own line.
+pub const VIRTIO_NET_IRQ: u64 = 2;
+
+/// RX is always queue 0, TX is always queue 1.
+/// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1950001
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const NUM_QUEUES: usize = 2;
+
+/// "Device handling of the network packet is described in terms of a handling a single buffer.
+/// ... struct virtio_net_hdr { ... }" Every frame is prefixed with this 12-byte header, matching
+/// the external drivers this device talks to.
+const NET_HDR_SIZE: u64 = 12;
+
+// Registers shared with virtio-blk's MMIO layout; see `devices::virtio` for the full register
+// table and doc comments.
+pub const VIRTIO_NET_MAGIC: u64 = VIRTIO_NET_BASE + 0x000;
+pub const VIRTIO_NET_VERSION: u64 = VIRTIO_NET_BASE + 0x004;
+/// Virtio Subsystem Device ID. 1 is network.
+pub const VIRTIO_NET_DEVICE_ID: u64 = VIRTIO_NET_BASE + 0x008;
+pub const VIRTIO_NET_VENDOR_ID: u64 = VIRTIO_NET_BASE + 0x00c;
+pub const VIRTIO_NET_DEVICE_FEATURES: u64 = VIRTIO_NET_BASE + 0x010;
+pub const VIRTIO_NET_DRIVER_FEATURES: u64 = VIRTIO_NET_BASE + 0x020;
+pub const VIRTIO_NET_GUEST_PAGE_SIZE: u64 = VIRTIO_NET_BASE + 0x028;
+pub const VIRTIO_NET_QUEUE_SEL: u64 = VIRTIO_NET_BASE + 0x030;
+pub const VIRTIO_NET_QUEUE_NUM_MAX: u64 = VIRTIO_NET_BASE + 0x034;
+pub const VIRTIO_NET_QUEUE_NUM: u64 = VIRTIO_NET_BASE + 0x038;
+pub const VIRTIO_NET_QUEUE_PFN: u64 = VIRTIO_NET_BASE + 0x040;
+pub const VIRTIO_NET_QUEUE_NOTIFY: u64 = VIRTIO_NET_BASE + 0x050;
+pub const VIRTIO_NET_STATUS: u64 = VIRTIO_NET_BASE + 0x070;
+
+/// A pluggable sink/source for Ethernet frames, so the device isn't tied to any particular host
+/// networking backend (a TAP device, a channel to another emulator instance, a pcap file, ...).
+pub trait NetBackend {
+    /// Hand a frame (without the virtio-net header) to the host for transmission.
+    fn send(&mut self, frame: &[u8]);
+    /// Poll for a frame (without the virtio-net header) the host has received for the guest.
+    fn try_recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// The backend `Bus` wires a freshly constructed `VirtioNet` up to by default: drops every frame
+/// the guest transmits and never has one to receive. An embedder that wants real connectivity
+/// replaces it by constructing its own `NetBackend` and installing it.
+pub(crate) struct NullNetBackend;
+
+impl NetBackend for NullNetBackend {
+    fn send(&mut self, _frame: &[u8]) {}
+
+    fn try_recv(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Paravirtualized network device.
+pub struct VirtioNet {
+    page_size: u32,
+    queue_sel: u32,
+    /// Per-queue register state and ring bookkeeping, shared with `devices::virtio`. Queue 0 is
+    /// RX, queue 1 is TX.
+    queues: [Virtqueue; NUM_QUEUES],
+    driver_features: u32,
+    status: u32,
+    backend: Box<dyn NetBackend>,
+}
+
+impl VirtioNet {
+    /// Create a new virtio-net device backed by `backend`.
+    pub fn new(backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            page_size: 0,
+            queue_sel: 0,
+            queues: [Virtqueue::new(), Virtqueue::new()],
+            driver_features: 0,
+            status: 0,
+            backend,
+        }
+    }
+
+    /// Return true if an interrupt is pending.
+    pub fn is_interrupting(&mut self) -> bool {
+        self.queues.iter_mut().any(|queue| queue.take_notified())
+    }
+
+    /// Read 4 bytes from virtio-net only if the addr is valid. Otherwise, return 0.
+    pub fn read(&self, addr: u64) -> u32 {
+        match addr {
+            VIRTIO_NET_MAGIC => 0x74726976,
+            VIRTIO_NET_VERSION => 0x1,
+            VIRTIO_NET_DEVICE_ID => 0x1, // network
+            VIRTIO_NET_VENDOR_ID => 0x554d4551,
+            // Legacy interface only (`VIRTIO_VERSION` reports 1): no features offered.
+            VIRTIO_NET_DEVICE_FEATURES => 0,
+            VIRTIO_NET_DRIVER_FEATURES => self.driver_features,
+            VIRTIO_NET_QUEUE_NUM_MAX => QUEUE_SIZE as u32,
+            VIRTIO_NET_QUEUE_PFN => self.selected_queue().map_or(0, Virtqueue::pfn),
+            VIRTIO_NET_STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    /// Write 4 bytes to virtio-net only if the addr is valid. Otherwise, does nothing.
+    pub fn write(&mut self, addr: u64, val: u32) {
+        match addr {
+            VIRTIO_NET_DRIVER_FEATURES => self.driver_features = val,
+            VIRTIO_NET_GUEST_PAGE_SIZE => self.page_size = val,
+            VIRTIO_NET_QUEUE_SEL => self.queue_sel = val,
+            VIRTIO_NET_QUEUE_NUM => self.selected_queue_mut().map_or((), |q| q.set_num(val)),
+            VIRTIO_NET_QUEUE_PFN => self.selected_queue_mut().map_or((), |q| q.set_pfn(val)),
+            // The payload is the queue index being notified, not the currently-selected queue. A
+            // guest writing an out-of-range index is ignored rather than indexed directly.
+            VIRTIO_NET_QUEUE_NOTIFY => self.queues.get_mut(val as usize).map_or((), Virtqueue::notify),
+            VIRTIO_NET_STATUS => self.status = val,
+            _ => {}
+        }
+    }
+
+    /// The queue `VIRTIO_NET_QUEUE_SEL` currently points at, or `None` if the driver selected an
+    /// out-of-range index.
+    fn selected_queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    /// Mutable counterpart of `selected_queue`.
+    fn selected_queue_mut(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Pull every frame newly available on the TX queue (queue 1) and hand each one, minus its
+    /// virtio-net header, to the backend for transmission. Batches from `last_avail_idx` up to
+    /// the driver's current `avail.idx` the same way `Virtio::disk_access` does, so a notify
+    /// following several queued frames doesn't transmit only the first (or a stale) one.
+    ///
+    /// Takes `cpu` only to reach guest memory for the DMA; device-local state (the queues, the
+    /// backend) is reached through `self` directly instead of re-entering `cpu.bus`. `Bus`
+    /// invokes this with `self` already taken out of its device slot, so re-fetching the device
+    /// through `cpu.bus` would hit an empty slot.
+    pub fn tx(&mut self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let (desc_addr, avail_addr, used_addr) = self.queues[TX_QUEUE].addrs(self.page_size);
+
+        let avail_idx = cpu.bus.read16(avail_addr.wrapping_add(2))? as u16;
+        while self.queues[TX_QUEUE].last_avail_idx != avail_idx {
+            let offset = self.queues[TX_QUEUE].last_avail_idx;
+            let head = cpu.bus.read16(
+                avail_addr
+                    .wrapping_add(4)
+                    .wrapping_add(offset as u64 % QUEUE_SIZE * 2),
+            )?;
+            self.queues[TX_QUEUE].last_avail_idx = offset.wrapping_add(1);
+
+            let chain = descriptor_chain(cpu, desc_addr, head)?;
+            let mut frame = Vec::new();
+            for desc in &chain {
+                for i in 0..desc.len {
+                    frame.push(cpu.bus.read8(desc.addr + i)?);
+                }
+            }
+
+            if frame.len() as u64 > NET_HDR_SIZE {
+                self.backend.send(&frame[NET_HDR_SIZE as usize..]);
+            }
+            push_used(cpu, used_addr, head, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Inject an incoming Ethernet `frame` into the RX queue (queue 0): prepend a zeroed
+    /// virtio-net header and copy it into the next device-writable descriptor chain, then raise
+    /// `VIRTIO_NET_IRQ`. Does nothing if the driver hasn't posted an RX descriptor yet — checking
+    /// `last_avail_idx` against the driver's `avail.idx` before consuming a ring slot, instead of
+    /// always consuming one, is what keeps an empty queue from reading a stale slot and DMAing
+    /// the frame into whatever garbage address it contains. The header+frame payload is copied
+    /// across as many device-writable descriptors in the chain as it takes, clamped to each
+    /// descriptor's own `len`, so a short buffer the driver posted is never overrun.
+    pub fn rx(&mut self, cpu: &mut Cpu, frame: &[u8]) -> Result<(), Exception> {
+        let (desc_addr, avail_addr, used_addr) = self.queues[RX_QUEUE].addrs(self.page_size);
+
+        let avail_idx = cpu.bus.read16(avail_addr.wrapping_add(2))? as u16;
+        let offset = self.queues[RX_QUEUE].last_avail_idx;
+        if offset == avail_idx {
+            // The driver hasn't posted an RX descriptor; drop the frame.
+            return Ok(());
+        }
+        let head = cpu.bus.read16(
+            avail_addr
+                .wrapping_add(4)
+                .wrapping_add(offset as u64 % QUEUE_SIZE * 2),
+        )?;
+        self.queues[RX_QUEUE].last_avail_idx = offset.wrapping_add(1);
+
+        let chain = descriptor_chain(cpu, desc_addr, head)?;
+        if !chain.iter().any(|desc| desc.flags & VIRTQ_DESC_F_WRITE != 0) {
+            // Not a device-writable buffer; the driver posted something unusable.
+            return Ok(());
+        }
+
+        let mut payload = vec![0u8; NET_HDR_SIZE as usize];
+        payload.extend_from_slice(frame);
+        let mut written = 0usize;
+        for desc in &chain {
+            if written >= payload.len() || desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+                continue;
+            }
+            let n = (desc.len as usize).min(payload.len() - written);
+            for i in 0..n as u64 {
+                cpu.bus.write8(desc.addr + i, payload[written + i as usize] as u64)?;
+            }
+            written += n;
+        }
+        push_used(cpu, used_addr, head, written as u64)?;
+        self.queues[RX_QUEUE].notify();
+        Ok(())
+    }
+
+    /// Poll the backend for an incoming frame and, if one arrived, inject it into the RX queue.
+    pub fn poll_rx(&mut self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let frame = self.backend.try_recv();
+        if let Some(frame) = frame {
+            self.rx(cpu, &frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        0x1
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn read(&self, addr: u64) -> u32 {
+        VirtioNet::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u64, val: u32) {
+        VirtioNet::write(self, addr, val)
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        VirtioNet::is_interrupting(self)
+    }
+
+    fn handle_notify(&mut self, queue_index: usize, cpu: &mut Cpu) -> Result<(), Exception> {
+        if queue_index == TX_QUEUE {
+            self.tx(cpu)?;
+        }
+        Ok(())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,222 @@
+//! The virtio_rng module contains a paravirtualized entropy device, giving the guest a working
+//! `/dev/hwrng` without requiring it to harvest entropy from (virtualized, and therefore mostly
+//! useless) hardware timing jitter.
+//!
+//! The virtio spec:
+//! http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1110002
+//! 5.4 Entropy Device:
+//! http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-2380004
+
+use std::any::Any;
+
+use crate::bus::VIRTIO_RNG_BASE;
+use crate::cpu::Cpu;
+use crate::devices::virtio_device::VirtioDevice;
+use crate::devices::virtqueue::{descriptor_chain, push_used, Virtqueue, QUEUE_SIZE, VIRTQ_DESC_F_WRITE};
+use crate::exception::Exception;
+
+/// The interrupt request of virtio-rng.
+pub const VIRTIO_RNG_IRQ: u64 = 3;
+
+/// The entropy device exposes a single request queue.
+const REQUEST_QUEUE: usize = 0;
+const NUM_QUEUES: usize = 1;
+
+/// The seed this device falls back to when the embedder never calls
+/// `Emulator::set_virtio_rng_seed`, chosen arbitrarily so a fresh emulator is still deterministic.
+const DEFAULT_SEED: u64 = 0xdead_beef_cafe_babe;
+
+// Registers shared with virtio-blk's MMIO layout; see `devices::virtio` for the full register
+// table and doc comments.
+pub const VIRTIO_RNG_MAGIC: u64 = VIRTIO_RNG_BASE + 0x000;
+pub const VIRTIO_RNG_VERSION: u64 = VIRTIO_RNG_BASE + 0x004;
+/// Virtio Subsystem Device ID. 4 is entropy.
+pub const VIRTIO_RNG_DEVICE_ID: u64 = VIRTIO_RNG_BASE + 0x008;
+pub const VIRTIO_RNG_VENDOR_ID: u64 = VIRTIO_RNG_BASE + 0x00c;
+pub const VIRTIO_RNG_DEVICE_FEATURES: u64 = VIRTIO_RNG_BASE + 0x010;
+pub const VIRTIO_RNG_DRIVER_FEATURES: u64 = VIRTIO_RNG_BASE + 0x020;
+pub const VIRTIO_RNG_GUEST_PAGE_SIZE: u64 = VIRTIO_RNG_BASE + 0x028;
+pub const VIRTIO_RNG_QUEUE_SEL: u64 = VIRTIO_RNG_BASE + 0x030;
+pub const VIRTIO_RNG_QUEUE_NUM_MAX: u64 = VIRTIO_RNG_BASE + 0x034;
+pub const VIRTIO_RNG_QUEUE_NUM: u64 = VIRTIO_RNG_BASE + 0x038;
+pub const VIRTIO_RNG_QUEUE_PFN: u64 = VIRTIO_RNG_BASE + 0x040;
+pub const VIRTIO_RNG_QUEUE_NOTIFY: u64 = VIRTIO_RNG_BASE + 0x050;
+pub const VIRTIO_RNG_STATUS: u64 = VIRTIO_RNG_BASE + 0x070;
+
+/// A small xorshift64 generator (shift triple 13/7/17) whose output is scrambled by the
+/// xorshift64* multiplier. Not cryptographically secure, but it's seedable, dependency-free,
+/// and good enough to unblock a guest kernel's entropy pool during boot.
+struct Prng {
+    state: u64,
+}
+
+impl Prng {
+    fn new(seed: u64) -> Self {
+        // xorshift stays stuck at a zero state, so nudge it off zero the same way other
+        // xorshift implementations do.
+        Self {
+            state: if seed == 0 { DEFAULT_SEED } else { seed },
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_u8(&mut self) -> u8 {
+        self.next_u64() as u8
+    }
+}
+
+/// Paravirtualized entropy device.
+pub struct VirtioRng {
+    page_size: u32,
+    queue_sel: u32,
+    queues: [Virtqueue; NUM_QUEUES],
+    driver_features: u32,
+    status: u32,
+    prng: Prng,
+}
+
+impl VirtioRng {
+    /// Create a new virtio-rng device seeded with `DEFAULT_SEED`.
+    pub fn new() -> Self {
+        Self {
+            page_size: 0,
+            queue_sel: 0,
+            queues: [Virtqueue::new()],
+            driver_features: 0,
+            status: 0,
+            prng: Prng::new(DEFAULT_SEED),
+        }
+    }
+
+    /// Reseed the PRNG, e.g. so a test can assert on the exact bytes the guest receives.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.prng = Prng::new(seed);
+    }
+
+    /// Return true if an interrupt is pending.
+    pub fn is_interrupting(&mut self) -> bool {
+        self.queues.iter_mut().any(|queue| queue.take_notified())
+    }
+
+    /// Read 4 bytes from virtio-rng only if the addr is valid. Otherwise, return 0.
+    pub fn read(&self, addr: u64) -> u32 {
+        match addr {
+            VIRTIO_RNG_MAGIC => 0x74726976,
+            VIRTIO_RNG_VERSION => 0x1,
+            VIRTIO_RNG_DEVICE_ID => 0x4, // entropy
+            VIRTIO_RNG_VENDOR_ID => 0x554d4551,
+            // Legacy interface only (`VIRTIO_RNG_VERSION` reports 1): no features offered.
+            VIRTIO_RNG_DEVICE_FEATURES => 0,
+            VIRTIO_RNG_DRIVER_FEATURES => self.driver_features,
+            VIRTIO_RNG_QUEUE_NUM_MAX => QUEUE_SIZE as u32,
+            VIRTIO_RNG_QUEUE_PFN => self.selected_queue().map_or(0, Virtqueue::pfn),
+            VIRTIO_RNG_STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    /// Write 4 bytes to virtio-rng only if the addr is valid. Otherwise, does nothing.
+    pub fn write(&mut self, addr: u64, val: u32) {
+        match addr {
+            VIRTIO_RNG_DRIVER_FEATURES => self.driver_features = val,
+            VIRTIO_RNG_GUEST_PAGE_SIZE => self.page_size = val,
+            VIRTIO_RNG_QUEUE_SEL => self.queue_sel = val,
+            VIRTIO_RNG_QUEUE_NUM => self.selected_queue_mut().map_or((), |q| q.set_num(val)),
+            VIRTIO_RNG_QUEUE_PFN => self.selected_queue_mut().map_or((), |q| q.set_pfn(val)),
+            // The payload is the queue index being notified, not the currently-selected queue. A
+            // guest writing an out-of-range index is ignored rather than indexed directly.
+            VIRTIO_RNG_QUEUE_NOTIFY => self.queues.get_mut(val as usize).map_or((), Virtqueue::notify),
+            VIRTIO_RNG_STATUS => self.status = val,
+            _ => {}
+        }
+    }
+
+    /// The queue `VIRTIO_RNG_QUEUE_SEL` currently points at, or `None` if the driver selected an
+    /// out-of-range index.
+    fn selected_queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    /// Mutable counterpart of `selected_queue`.
+    fn selected_queue_mut(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Fill every device-writable descriptor in each newly available chain with random bytes, post
+    /// each chain's total length written to the used ring, and let `is_interrupting` raise
+    /// `VIRTIO_RNG_IRQ`.
+    ///
+    /// Takes `cpu` only to reach guest memory for the DMA; device-local state (the queues, the
+    /// PRNG) is reached through `self` directly instead of re-entering `cpu.bus`. `Bus` invokes
+    /// this with `self` already taken out of its device slot, so re-fetching the device through
+    /// `cpu.bus` would hit an empty slot.
+    pub fn handle_request(&mut self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let (desc_addr, avail_addr, used_addr) =
+            self.queues[REQUEST_QUEUE].addrs(self.page_size);
+
+        let avail_idx = cpu.bus.read16(avail_addr.wrapping_add(2))? as u16;
+        while self.queues[REQUEST_QUEUE].last_avail_idx != avail_idx {
+            let offset = self.queues[REQUEST_QUEUE].last_avail_idx;
+            let head = cpu.bus.read16(
+                avail_addr
+                    .wrapping_add(4)
+                    .wrapping_add(offset as u64 % QUEUE_SIZE * 2),
+            )?;
+            self.queues[REQUEST_QUEUE].last_avail_idx = offset.wrapping_add(1);
+
+            let chain = descriptor_chain(cpu, desc_addr, head)?;
+            let mut len = 0;
+            for desc in &chain {
+                if desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+                    continue;
+                }
+                for i in 0..desc.len {
+                    let byte = self.prng.next_u8();
+                    cpu.bus.write8(desc.addr + i, byte as u64)?;
+                }
+                len += desc.len;
+            }
+
+            push_used(cpu, used_addr, head, len)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        0x4
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn read(&self, addr: u64) -> u32 {
+        VirtioRng::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u64, val: u32) {
+        VirtioRng::write(self, addr, val)
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        VirtioRng::is_interrupting(self)
+    }
+
+    fn handle_notify(&mut self, _queue_index: usize, cpu: &mut Cpu) -> Result<(), Exception> {
+        self.handle_request(cpu)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
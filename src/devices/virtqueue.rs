@@ -0,0 +1,242 @@
+//! Queue machinery shared by every virtio device: the per-queue MMIO register state and the
+//! descriptor/avail-ring/used-ring walking that used to live solely in the virtio-blk module.
+//! Split out so virtio-net (`virtio_net`) and other devices don't have to duplicate it.
+
+use crate::cpu::Cpu;
+use crate::exception::Exception;
+
+/// The size of `VRingDesc` struct.
+pub(crate) const VRING_DESC_SIZE: u64 = 16;
+/// The number of virtio descriptors. It must be a power of two.
+pub(crate) const QUEUE_SIZE: u64 = 8;
+
+/// This marks a buffer as continuing via the `next` field.
+pub(crate) const VIRTQ_DESC_F_NEXT: u64 = 1;
+/// This marks a buffer as device write-only (otherwise device read-only).
+pub(crate) const VIRTQ_DESC_F_WRITE: u64 = 2;
+/// This means the buffer contains a list of buffer descriptors.
+pub(crate) const VIRTQ_DESC_F_INDIRECT: u64 = 4;
+
+/// "The descriptor table refers to the buffers the driver is using for the device. addr is a
+/// physical address, and the buffers can be chained via next. Each descriptor describes a buffer
+/// which is read-only for the device (“device-readable”) or write-only for the device
+/// (“device-writable”), but a chain of descriptors can contain both device-readable and
+/// device-writable buffers."
+///
+/// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-300005
+///
+/// ```c
+/// struct virtq_desc {
+///   /* Address (guest-physical). */
+///   le64 addr;
+///   /* Length. */
+///   le32 len;
+///
+///   /* This marks a buffer as continuing via the next field. */
+///   #define VIRTQ_DESC_F_NEXT   1
+///   /* This marks a buffer as device write-only (otherwise device read-only). */
+///   #define VIRTQ_DESC_F_WRITE     2
+///   /* This means the buffer contains a list of buffer descriptors. */
+///   #define VIRTQ_DESC_F_INDIRECT   4
+///   /* The flags as indicated above. */
+///   le16 flags;
+///   /* Next field if flags & NEXT */
+///   le16 next;
+/// };
+/// ```
+pub(crate) struct VirtqDesc {
+    /// 64-bit address.
+    pub(crate) addr: u64,
+    /// 32-bit length.
+    pub(crate) len: u64,
+    /// 16-bit flags.
+    pub(crate) flags: u64,
+    /// 16-bit next.
+    pub(crate) next: u64,
+}
+
+impl VirtqDesc {
+    /// Create a new virtqueue descriptor based on the address that stores the content of the descriptor.
+    pub(crate) fn new(cpu: &Cpu, addr: u64) -> Result<Self, Exception> {
+        Ok(Self {
+            addr: cpu.bus.read64(addr)?,
+            len: cpu.bus.read32(addr.wrapping_add(8))?,
+            flags: cpu.bus.read16(addr.wrapping_add(12))?,
+            next: cpu.bus.read16(addr.wrapping_add(14))?,
+        })
+    }
+}
+
+/// Walk the descriptor chain starting at `head` in the table at `desc_addr`, following `next`
+/// up to `QUEUE_SIZE` hops so a malformed ring can't spin the device forever. A descriptor
+/// flagged `VIRTQ_DESC_F_INDIRECT` switches the walk into the sub-table at `desc.addr`, which is
+/// bounded by its own `desc.len / VRING_DESC_SIZE` entry count instead of `QUEUE_SIZE`, since
+/// indirect tables exist precisely to describe chains longer than the queue size.
+pub(crate) fn descriptor_chain(
+    cpu: &mut Cpu,
+    desc_addr: u64,
+    head: u64,
+) -> Result<Vec<VirtqDesc>, Exception> {
+    let mut chain = Vec::new();
+    let mut desc_index = head;
+    for _ in 0..QUEUE_SIZE {
+        let desc = VirtqDesc::new(cpu, desc_addr + VRING_DESC_SIZE * desc_index)?;
+
+        if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            indirect_descriptor_chain(cpu, &mut chain, desc.addr, desc.len / VRING_DESC_SIZE)?;
+            break;
+        }
+
+        let has_next = desc.flags & VIRTQ_DESC_F_NEXT != 0;
+        let next = desc.next;
+        chain.push(desc);
+        if !has_next {
+            break;
+        }
+        desc_index = next;
+    }
+    Ok(chain)
+}
+
+/// Walk the `count`-entry indirect sub-table at `table_addr`, following `next` within it, and
+/// append every descriptor to `chain`. A table cannot itself contain another indirect
+/// descriptor (2.4.5.3.1 "Driver Requirements: Indirect Descriptors").
+fn indirect_descriptor_chain(
+    cpu: &mut Cpu,
+    chain: &mut Vec<VirtqDesc>,
+    table_addr: u64,
+    count: u64,
+) -> Result<(), Exception> {
+    let mut desc_index = 0;
+    for _ in 0..count {
+        let desc = VirtqDesc::new(cpu, table_addr + VRING_DESC_SIZE * desc_index)?;
+        let has_next = desc.flags & VIRTQ_DESC_F_NEXT != 0;
+        let next = desc.next;
+        chain.push(desc);
+        if !has_next {
+            break;
+        }
+        desc_index = next;
+    }
+    Ok(())
+}
+
+/// Append a `virtq_used_elem { id, len }` to the used ring at `used_addr` and bump `used.idx`.
+///
+/// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-400008
+/// struct virtq_used {
+///   #define VIRTQ_USED_F_NO_NOTIFY  1
+///   le16 flags;
+///   le16 idx;
+///   struct virtq_used_elem ring[ /* Queue Size */];
+///   le16 avail_event; /* Only if VIRTIO_F_EVENT_IDX */
+/// };
+pub(crate) fn push_used(cpu: &mut Cpu, used_addr: u64, id: u64, len: u64) -> Result<(), Exception> {
+    let used_idx = cpu.bus.read16(used_addr.wrapping_add(2))?;
+    let elem_addr = used_addr + 4 + (used_idx % QUEUE_SIZE) * 8;
+    cpu.bus.write32(elem_addr, id)?;
+    cpu.bus.write32(elem_addr.wrapping_add(4), len)?;
+    cpu.bus
+        .write16(used_addr.wrapping_add(2), used_idx.wrapping_add(1))?;
+    Ok(())
+}
+
+/// The per-queue MMIO register state shared by every virtio device: size, the legacy PFN, the
+/// version 2 Descriptor/Driver/Device Area addresses, the ready flag, and where the device last
+/// left off reading the available ring.
+#[derive(Default)]
+pub(crate) struct Virtqueue {
+    num: u32,
+    pfn: u32,
+    ready: u32,
+    desc_addr: u64,
+    driver_addr: u64,
+    device_addr: u64,
+    notified: bool,
+    /// The last `avail.idx` this queue has processed, so a batch of notifications can pick up
+    /// every newly available head instead of just the most recent one. `avail.idx` is a 16-bit
+    /// value that wraps around free-running (2.6.8 "the idx field always increases, and the
+    /// available ring uses the modulo operation"), so this has to wrap at the same width: a
+    /// wider counter would never compare equal to a wrapped `avail.idx` again once the driver
+    /// has posted more than 65536 descriptors.
+    pub(crate) last_avail_idx: u16,
+}
+
+impl Virtqueue {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn num(&self) -> u32 {
+        self.num
+    }
+
+    pub(crate) fn set_num(&mut self, num: u32) {
+        self.num = num;
+    }
+
+    pub(crate) fn pfn(&self) -> u32 {
+        self.pfn
+    }
+
+    pub(crate) fn set_pfn(&mut self, pfn: u32) {
+        self.pfn = pfn;
+    }
+
+    pub(crate) fn ready(&self) -> u32 {
+        self.ready
+    }
+
+    pub(crate) fn set_ready(&mut self, ready: u32) {
+        self.ready = ready;
+    }
+
+    pub(crate) fn set_desc_low(&mut self, val: u32) {
+        self.desc_addr = (self.desc_addr & 0xffff_ffff_0000_0000) | val as u64;
+    }
+
+    pub(crate) fn set_desc_high(&mut self, val: u32) {
+        self.desc_addr = (self.desc_addr & 0xffff_ffff) | ((val as u64) << 32);
+    }
+
+    pub(crate) fn set_driver_low(&mut self, val: u32) {
+        self.driver_addr = (self.driver_addr & 0xffff_ffff_0000_0000) | val as u64;
+    }
+
+    pub(crate) fn set_driver_high(&mut self, val: u32) {
+        self.driver_addr = (self.driver_addr & 0xffff_ffff) | ((val as u64) << 32);
+    }
+
+    pub(crate) fn set_device_low(&mut self, val: u32) {
+        self.device_addr = (self.device_addr & 0xffff_ffff_0000_0000) | val as u64;
+    }
+
+    pub(crate) fn set_device_high(&mut self, val: u32) {
+        self.device_addr = (self.device_addr & 0xffff_ffff) | ((val as u64) << 32);
+    }
+
+    /// Mark this queue as having a buffer waiting to be processed.
+    pub(crate) fn notify(&mut self) {
+        self.notified = true;
+    }
+
+    /// Return and clear whether this queue was notified since the last call.
+    pub(crate) fn take_notified(&mut self) -> bool {
+        let notified = self.notified;
+        self.notified = false;
+        notified
+    }
+
+    /// Return the (descriptor, available ring, used ring) addresses for this queue, picking the
+    /// version 2 independently-addressed rings when the driver has programmed them and falling
+    /// back to the legacy fixed `+0x40`/`+4096` offsets from a single `pfn * page_size` page
+    /// otherwise.
+    pub(crate) fn addrs(&self, page_size: u32) -> (u64, u64, u64) {
+        if self.desc_addr != 0 {
+            (self.desc_addr, self.driver_addr, self.device_addr)
+        } else {
+            let desc_addr = self.pfn as u64 * page_size as u64;
+            (desc_addr, desc_addr + 0x40, desc_addr + 4096)
+        }
+    }
+}
@@ -38,6 +38,24 @@ impl Emulator {
         self.cpu.bus.set_disk(data);
     }
 
+    /// Declare additional virtio-blk features the disk device offers, e.g. `VIRTIO_BLK_F_RO` to
+    /// mount the disk read-only.
+    pub fn set_virtio_device_features(&mut self, features: u64) {
+        self.cpu.bus.virtio().set_device_features(features);
+    }
+
+    /// Opt the virtio-blk device into the version 1.0 (non-legacy) MMIO transport by reporting 2
+    /// through `VIRTIO_VERSION`. Defaults to the legacy version 1 transport.
+    pub fn set_virtio_version(&mut self, version: u32) {
+        self.cpu.bus.virtio().set_version(version);
+    }
+
+    /// Reseed the virtio-rng device, so a test can assert on the exact bytes a guest reads from
+    /// `/dev/hwrng`.
+    pub fn set_virtio_rng_seed(&mut self, seed: u64) {
+        self.cpu.bus.virtio_rng().set_seed(seed);
+    }
+
     /// Set the program counter to the CPU field.
     pub fn set_pc(&mut self, pc: u64) {
         self.cpu.pc = pc;